@@ -0,0 +1,231 @@
+//  Copyright 2019 The Tari Project
+//
+//  Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+//  following conditions are met:
+//
+//  1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+//  disclaimer.
+//
+//  2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+//  following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+//  3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+//  products derived from this software without specific prior written permission.
+//
+//  THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+//  INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+//  DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+//  SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+//  SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+//  WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+//  USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Counters shared across the comms stack's services, surfaced through
+//! [CommsServices::metrics](super::builder::CommsServices::metrics) and, optionally, a background
+//! thread that periodically pushes a snapshot to every registered [StatusSink] (so they can be
+//! exported to Prometheus, logged, or whatever else an operator wires up).
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use super::task_manager::{ShutdownSignal, TaskManager};
+
+/// How finely [MetricsRegistry::start_reporting]'s sleep between ticks is broken up so it can
+/// notice the shutdown signal promptly instead of only after a whole (possibly long) report
+/// interval has elapsed.
+const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+/// Sleep for `total`, but in [SLEEP_STEP]-sized increments, returning early as soon as
+/// `shutdown_signal` trips rather than always sleeping the full duration.
+fn sleep_in_increments(total: Duration, step: Duration, shutdown_signal: &ShutdownSignal) {
+    let zero = Duration::from_millis(0);
+    let mut remaining = total;
+    while remaining > zero && !shutdown_signal.is_triggered() {
+        let this_step = if step < remaining { step } else { remaining };
+        thread::sleep(this_step);
+        remaining -= this_step;
+    }
+}
+
+/// Counters updated by the various services `CommsBuilder` constructs. Every `record_*` method is
+/// cheap enough to call on the hot path - each is a single atomic add.
+#[derive(Default)]
+pub struct CommsMetrics {
+    connections_established: AtomicU64,
+    connections_closed: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    dispatcher_errors: AtomicU64,
+}
+
+impl CommsMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_connection_established(&self) {
+        self.connections_established.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_connection_closed(&self) {
+        self.connections_closed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_message_received(&self) {
+        self.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dispatcher_error(&self) {
+        self.dispatcher_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections_established: self.connections_established.load(Ordering::Relaxed),
+            connections_closed: self.connections_closed.load(Ordering::Relaxed),
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            dispatcher_errors: self.dispatcher_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time copy of every [CommsMetrics] counter, returned by
+/// [CommsServices::metrics](super::builder::CommsServices::metrics) and handed to every
+/// [StatusSink].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub connections_established: u64,
+    pub connections_closed: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub dispatcher_errors: u64,
+}
+
+/// Receives a [MetricsSnapshot] each time [MetricsRegistry]'s periodic reporter fires.
+pub trait StatusSink: Send + Sync {
+    fn report(&self, snapshot: &MetricsSnapshot);
+}
+
+impl<F> StatusSink for F
+where F: Fn(&MetricsSnapshot) + Send + Sync
+{
+    fn report(&self, snapshot: &MetricsSnapshot) {
+        (self)(snapshot)
+    }
+}
+
+/// Owns the shared [CommsMetrics] counters and the set of [StatusSink]s that should be told about
+/// them. `CommsBuilder::build` constructs exactly one of these per node so every `make_*`
+/// constructor can be handed the same `Arc<CommsMetrics>` to record against.
+#[derive(Clone)]
+pub struct MetricsRegistry {
+    metrics: Arc<CommsMetrics>,
+    sinks: Arc<Mutex<Vec<Box<dyn StatusSink>>>>,
+}
+
+impl MetricsRegistry {
+    pub fn new() -> Self {
+        Self {
+            metrics: Arc::new(CommsMetrics::new()),
+            sinks: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// The shared counters; handed to services as they are constructed so they can record
+    /// against it directly.
+    pub fn metrics(&self) -> Arc<CommsMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Register a sink to be sent a [MetricsSnapshot] every time the periodic reporter fires.
+    /// Has no effect unless [MetricsRegistry::start_reporting] is also called.
+    pub fn register_sink<S>(&self, sink: S)
+    where S: StatusSink + 'static {
+        self.sinks.lock().unwrap().push(Box::new(sink));
+    }
+
+    /// Spawn the background thread that pushes a snapshot to every registered sink every
+    /// `interval`, registering it with `task_manager` so it is joined on shutdown like every
+    /// other background thread this crate starts.
+    pub fn start_reporting(&self, task_manager: &TaskManager, interval: Duration) {
+        let metrics = self.metrics.clone();
+        let sinks = self.sinks.clone();
+        let shutdown_signal = task_manager.shutdown_signal();
+
+        task_manager.spawn("metrics_reporter", move || {
+            while !shutdown_signal.is_triggered() {
+                // Sleep in small increments rather than one `thread::sleep(interval)`, the same
+                // way rpc.rs's worker_loop polls its connector - otherwise a long `interval` (e.g.
+                // 30s) leaves this thread unable to notice shutdown until it wakes up, which can
+                // easily outlast CommsServices::shutdown's own join timeout.
+                sleep_in_increments(interval, SLEEP_STEP, &shutdown_signal);
+                if shutdown_signal.is_triggered() {
+                    break;
+                }
+                let snapshot = metrics.snapshot();
+                for sink in sinks.lock().unwrap().iter() {
+                    sink.report(&snapshot);
+                }
+            }
+            Ok::<(), ()>(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_starts_at_zero() {
+        let metrics = CommsMetrics::new();
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+    }
+
+    #[test]
+    fn snapshot_reflects_recorded_metrics() {
+        let metrics = CommsMetrics::new();
+        metrics.record_connection_established();
+        metrics.record_connection_closed();
+        metrics.record_message_sent();
+        metrics.record_message_sent();
+        metrics.record_dispatcher_error();
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.connections_established, 1);
+        assert_eq!(snapshot.connections_closed, 1);
+        assert_eq!(snapshot.messages_sent, 2);
+        assert_eq!(snapshot.dispatcher_errors, 1);
+    }
+
+    #[test]
+    fn start_reporting_stops_promptly_on_shutdown_even_with_a_long_interval() {
+        let registry = MetricsRegistry::new();
+        let task_manager = TaskManager::new();
+
+        // A report interval far longer than TaskManager::join_all's own timeout - if the reporter
+        // only checked for shutdown between full-length sleeps, join_all would have to give up on
+        // it rather than observe a clean join.
+        registry.start_reporting(&task_manager, Duration::from_secs(3600));
+
+        let results = task_manager.join_all(Duration::from_secs(2));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].outcome.is_ok());
+    }
+}
@@ -0,0 +1,398 @@
+//  Copyright 2019 The Tari Project
+//
+//  Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+//  following conditions are met:
+//
+//  1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+//  disclaimer.
+//
+//  2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+//  following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+//  3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+//  products derived from this software without specific prior written permission.
+//
+//  THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+//  INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+//  DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+//  SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+//  SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+//  WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+//  USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! A request/response layer on top of the fire-and-forget [OutboundMessageService] and
+//! [InboundMessageBroker]/[DomainConnector] plumbing.
+//!
+//! Every outgoing request is tagged with a unique [RequestId]; the id travels with the reply so
+//! that it can be routed back to the [RpcClient::send_request] call that is waiting on it,
+//! instead of the caller having to manually pair up an outbound send with an inbound connector
+//! read. Handlers registered with [RpcClient::register_handler] let the same message type answer
+//! requests symmetrically. A request can be cancelled with [RpcClient::cancel] - reserve its id
+//! up front with [RpcClient::reserve_request_id] if it needs to be cancellable before
+//! [RpcClient::send_request_as] is even called.
+
+use super::{
+    metrics::CommsMetrics,
+    task_manager::{ShutdownSignal, TaskManager},
+};
+use crate::{
+    builder::CommsServicesError,
+    outbound_message_service::{outbound_message_service::OutboundMessageService, OutboundError},
+    types::CommsPublicKey,
+    DomainConnector,
+};
+use derive_error::Error;
+use log::*;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+const LOG_TARGET: &'static str = "comms::builder::rpc";
+
+/// Uniquely identifies one in-flight request/response exchange.
+pub type RequestId = u64;
+
+#[derive(Debug, Error)]
+pub enum RpcError {
+    /// No response arrived for this request before its timeout elapsed
+    Timeout,
+    /// The request was cancelled before a response arrived
+    Cancelled,
+    #[error(no_from)]
+    SendFailed(OutboundError),
+    /// No handler is registered for this message type
+    HandlerNotFound,
+    /// Failed to (de)serialize an RPC envelope
+    CodecError,
+    CommsServicesError(CommsServicesError),
+}
+
+#[derive(Serialize, Deserialize)]
+enum RpcEnvelope {
+    Request {
+        id: RequestId,
+        /// The sender's public key, so the handler on the other end knows where to route the
+        /// response back to.
+        from: CommsPublicKey,
+        body: Vec<u8>,
+    },
+    Response {
+        id: RequestId,
+        body: Vec<u8>,
+    },
+}
+
+/// A handler that answers an incoming RPC request with a response payload.
+pub trait RpcHandler: Send + Sync {
+    fn handle(&self, request: &[u8]) -> Result<Vec<u8>, RpcError>;
+}
+
+impl<F> RpcHandler for F
+where F: Fn(&[u8]) -> Result<Vec<u8>, RpcError> + Send + Sync
+{
+    fn handle(&self, request: &[u8]) -> Result<Vec<u8>, RpcError> {
+        (self)(request)
+    }
+}
+
+/// What [RpcClient] knows about one [RequestId] that has been reserved but not yet answered.
+enum PendingSlot {
+    /// Waiting on a response; deliver the body down this channel when it arrives.
+    Pending(mpsc::Sender<Vec<u8>>),
+    /// [RpcClient::cancel] ran before [RpcClient::send_request_as] reserved this id - left as a
+    /// tombstone so the eventual `send_request_as` call (which removes and checks this entry
+    /// under the same lock `cancel` inserted it with) sees the cancellation instead of racing
+    /// past it.
+    Cancelled,
+}
+
+type PendingResponses = Arc<Mutex<HashMap<RequestId, PendingSlot>>>;
+
+/// A typed call/response layer for a single `MType` registered with [CommsRoutes](super::CommsRoutes).
+///
+/// Construct one with [crate::builder::CommsServices::create_rpc_client], which wires it up to
+/// that message type's [DomainConnector] and the node's [OutboundMessageService], and registers
+/// its background worker with the node's [TaskManager] so it is joined (and stops polling for
+/// frames) on shutdown rather than running forever.
+pub struct RpcClient<MType> {
+    message_type: MType,
+    own_public_key: CommsPublicKey,
+    outbound: Arc<OutboundMessageService>,
+    next_id: Arc<AtomicU64>,
+    /// Both in-flight requests and ids cancelled ahead of [RpcClient::send_request_as], under one
+    /// lock - see [PendingSlot].
+    pending: PendingResponses,
+    handlers: Arc<Mutex<HashMap<&'static str, Box<dyn RpcHandler>>>>,
+    metrics: Arc<CommsMetrics>,
+}
+
+impl<MType> RpcClient<MType>
+where MType: Serialize + DeserializeOwned + Clone + Send + 'static
+{
+    pub(super) fn new(
+        message_type: MType,
+        own_public_key: CommsPublicKey,
+        outbound: Arc<OutboundMessageService>,
+        connector: DomainConnector<'static>,
+        metrics: Arc<CommsMetrics>,
+        task_manager: &TaskManager,
+    ) -> Self
+    {
+        let pending: PendingResponses = Arc::new(Mutex::new(HashMap::new()));
+        let handlers: Arc<Mutex<HashMap<&'static str, Box<dyn RpcHandler>>>> = Arc::new(Mutex::new(HashMap::new()));
+
+        {
+            let pending = pending.clone();
+            let handlers = handlers.clone();
+            let outbound = outbound.clone();
+            let message_type = message_type.clone();
+            let metrics = metrics.clone();
+            let shutdown_signal = task_manager.shutdown_signal();
+            task_manager.spawn("rpc_client_worker", move || {
+                Self::worker_loop(connector, pending, handlers, outbound, message_type, metrics, shutdown_signal)
+            });
+        }
+
+        Self {
+            message_type,
+            own_public_key,
+            outbound,
+            next_id: Arc::new(AtomicU64::new(1)),
+            pending,
+            handlers,
+            metrics,
+        }
+    }
+
+    /// Register a handler that answers requests for the default (unnamed) RPC method on this
+    /// message type. Only one handler may be registered per client.
+    pub fn register_handler<H>(&self, handler: H)
+    where H: RpcHandler + 'static {
+        self.handlers.lock().unwrap().insert("default", Box::new(handler));
+    }
+
+    /// Reserve a [RequestId] without sending anything yet, so it can be handed to
+    /// [RpcClient::cancel] before (or racing) the eventual [RpcClient::send_request_as] call.
+    pub fn reserve_request_id(&self) -> RequestId {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Send `payload` to `dest` and block (up to `timeout`) for the correlated response.
+    pub fn send_request(
+        &self,
+        dest: CommsPublicKey,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RpcError>
+    {
+        self.send_request_as(self.reserve_request_id(), dest, payload, timeout)
+    }
+
+    /// As [RpcClient::send_request], but using a [RequestId] obtained up front from
+    /// [RpcClient::reserve_request_id] - so the caller can [RpcClient::cancel] it even before this
+    /// call starts blocking.
+    pub fn send_request_as(
+        &self,
+        id: RequestId,
+        dest: CommsPublicKey,
+        payload: Vec<u8>,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, RpcError>
+    {
+        let (tx, rx) = mpsc::channel();
+        {
+            // Check the tombstone a racing RpcClient::cancel might have left and reserve this id
+            // as pending in the same critical section, so a cancel that lands between the two
+            // can't be missed the way it would be with two separate lock acquisitions.
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(PendingSlot::Cancelled) = pending.remove(&id) {
+                return Err(RpcError::Cancelled);
+            }
+            pending.insert(id, PendingSlot::Pending(tx));
+        }
+
+        let envelope = RpcEnvelope::Request {
+            id,
+            from: self.own_public_key.clone(),
+            body: payload,
+        };
+        if let Err(err) = self.send_envelope(dest, &envelope) {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(err);
+        }
+
+        match rx.recv_timeout(timeout) {
+            Ok(body) => Ok(body),
+            Err(RecvTimeoutError::Timeout) => {
+                self.pending.lock().unwrap().remove(&id);
+                Err(RpcError::Timeout)
+            },
+            // The entry was removed out from under us - the only way that happens is
+            // RpcClient::cancel dropping the sender and hanging up this end of the channel.
+            Err(RecvTimeoutError::Disconnected) => Err(RpcError::Cancelled),
+        }
+    }
+
+    /// Cancel a request. If `id` is already in flight, this causes the
+    /// [RpcClient::send_request]/[RpcClient::send_request_as] call blocked on it to return
+    /// [RpcError::Cancelled] immediately. If `id` was only [RpcClient::reserve_request_id]'d so
+    /// far, the eventual [RpcClient::send_request_as] call for it returns [RpcError::Cancelled]
+    /// without sending anything. Returns `false` only if an earlier call cancelled `id` while it
+    /// was still only reserved; cancelling an in-flight request removes its entry outright, so a
+    /// repeat call after that returns `true` again.
+    pub fn cancel(&self, id: RequestId) -> bool {
+        let mut pending = self.pending.lock().unwrap();
+        match pending.remove(&id) {
+            Some(PendingSlot::Pending(_)) => true,
+            Some(PendingSlot::Cancelled) => {
+                // Already cancelled by an earlier call - put the tombstone back and report that
+                // this call didn't newly cancel anything, matching HashSet::insert's semantics.
+                pending.insert(id, PendingSlot::Cancelled);
+                false
+            },
+            None => {
+                pending.insert(id, PendingSlot::Cancelled);
+                true
+            },
+        }
+    }
+
+    fn send_envelope(&self, dest: CommsPublicKey, envelope: &RpcEnvelope) -> Result<(), RpcError> {
+        let buf = bincode::serialize(envelope).map_err(|_| RpcError::CodecError)?;
+        let result = self.outbound.send(dest, self.message_type.clone(), buf);
+        if result.is_ok() {
+            self.metrics.record_message_sent();
+        }
+        result.map_err(RpcError::SendFailed)
+    }
+
+    fn worker_loop(
+        connector: DomainConnector<'static>,
+        pending: PendingResponses,
+        handlers: Arc<Mutex<HashMap<&'static str, Box<dyn RpcHandler>>>>,
+        outbound: Arc<OutboundMessageService>,
+        message_type: MType,
+        metrics: Arc<CommsMetrics>,
+        shutdown_signal: ShutdownSignal,
+    ) -> Result<(), ()>
+    {
+        while !shutdown_signal.is_triggered() {
+            let frame = match connector.receive_timeout(Duration::from_secs(1)) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => continue,
+                Err(_) => break,
+            };
+
+            let envelope: RpcEnvelope = match bincode::deserialize(&frame) {
+                Ok(envelope) => envelope,
+                Err(_) => {
+                    warn!(target: LOG_TARGET, "Dropping RPC frame that failed to decode");
+                    continue;
+                },
+            };
+            metrics.record_message_received();
+
+            match envelope {
+                RpcEnvelope::Response { id, body } => {
+                    if let Some(PendingSlot::Pending(responder)) = pending.lock().unwrap().remove(&id) {
+                        let _ = responder.send(body);
+                    }
+                },
+                RpcEnvelope::Request { id, from, body } => {
+                    let reply_body = {
+                        let handlers = handlers.lock().unwrap();
+                        match handlers.get("default") {
+                            Some(handler) => handler.handle(&body).unwrap_or_default(),
+                            None => {
+                                warn!(target: LOG_TARGET, "No RPC handler registered for request {}", id);
+                                continue;
+                            },
+                        }
+                    };
+
+                    let reply = RpcEnvelope::Response { id, body: reply_body };
+                    if let Ok(buf) = bincode::serialize(&reply) {
+                        if outbound.send(from, message_type.clone(), buf).is_ok() {
+                            metrics.record_message_sent();
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        builder::{CommsBuilder, CommsRoutes, CommsServices},
+        peer_manager::NodeIdentity,
+    };
+    use std::thread;
+
+    #[test]
+    fn request_ids_increase_monotonically() {
+        let (_comms_services, client) = test_rpc_client();
+        let a = client.reserve_request_id();
+        let b = client.reserve_request_id();
+        assert!(b > a);
+    }
+
+    /// A started node with one message type registered, and an [RpcClient] for it - kept alive
+    /// together so the client's worker thread and outbound socket stay up for the test.
+    fn test_rpc_client() -> (CommsServices<String>, RpcClient<String>) {
+        let comms_services = CommsBuilder::new()
+            .with_routes(CommsRoutes::new().register("rpc".to_owned()))
+            .with_node_identity(NodeIdentity::random_for_test(None))
+            .build()
+            .unwrap()
+            .start()
+            .unwrap();
+        let client = comms_services.create_rpc_client("rpc".to_owned()).unwrap();
+        (comms_services, client)
+    }
+
+    #[test]
+    fn cancelling_a_pending_request_disconnects_its_receiver() {
+        let (_comms_services, client) = test_rpc_client();
+        let client = Arc::new(client);
+        let id = client.reserve_request_id();
+        let dest = NodeIdentity::random_for_test(None).public_key().clone();
+
+        let sender = {
+            let client = client.clone();
+            thread::spawn(move || client.send_request_as(id, dest, b"ping".to_vec(), Duration::from_secs(5)))
+        };
+
+        // Give send_request_as a moment to reserve its PendingSlot::Pending entry before this
+        // races in to cancel it.
+        thread::sleep(Duration::from_millis(50));
+        assert!(client.cancel(id));
+        assert!(matches!(sender.join().unwrap(), Err(RpcError::Cancelled)));
+    }
+
+    #[test]
+    fn cancelling_a_reserved_but_unsent_request_is_remembered() {
+        let (_comms_services, client) = test_rpc_client();
+        let id = client.reserve_request_id();
+        let dest = NodeIdentity::random_for_test(None).public_key().clone();
+
+        // Cancel before send_request_as ever reserves a pending slot for this id.
+        assert!(client.cancel(id));
+        // A second cancel of the same id finds the tombstone left by the first, not a fresh id.
+        assert!(!client.cancel(id));
+
+        let result = client.send_request_as(id, dest, b"ping".to_vec(), Duration::from_millis(50));
+        assert!(matches!(result, Err(RpcError::Cancelled)));
+    }
+}
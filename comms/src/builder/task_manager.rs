@@ -0,0 +1,232 @@
+//  Copyright 2019 The Tari Project
+//
+//  Redistribution and use in source and binary forms, with or without modification, are permitted provided that the
+//  following conditions are met:
+//
+//  1. Redistributions of source code must retain the above copyright notice, this list of conditions and the following
+//  disclaimer.
+//
+//  2. Redistributions in binary form must reproduce the above copyright notice, this list of conditions and the
+//  following disclaimer in the documentation and/or other materials provided with the distribution.
+//
+//  3. Neither the name of the copyright holder nor the names of its contributors may be used to endorse or promote
+//  products derived from this software without specific prior written permission.
+//
+//  THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS" AND ANY EXPRESS OR IMPLIED WARRANTIES,
+//  INCLUDING, BUT NOT LIMITED TO, THE IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+//  DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE FOR ANY DIRECT, INDIRECT, INCIDENTAL,
+//  SPECIAL, EXEMPLARY, OR CONSEQUENTIAL DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+//  SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER CAUSED AND ON ANY THEORY OF LIABILITY,
+//  WHETHER IN CONTRACT, STRICT LIABILITY, OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE
+//  USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Tracks every background thread [CommsServices](super::builder::CommsServices) starts, so
+//! shutdown can signal, join and report on all of them instead of each being handled ad hoc.
+
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+        Arc,
+        Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+/// A cheaply-clonable handle that background tasks can poll to find out whether the node is
+/// shutting down.
+#[derive(Clone)]
+pub struct ShutdownSignal(Arc<AtomicBool>);
+
+impl ShutdownSignal {
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// The outcome of one task that [TaskManager] was tracking, reported back to the caller of
+/// [TaskManager::join_all].
+pub struct TaskResult {
+    pub name: &'static str,
+    pub essential: bool,
+    pub outcome: Result<(), String>,
+}
+
+/// Owns the lifecycle of every background thread the comms stack spawns.
+///
+/// Each thread is tracked as either best-effort or essential (the node cannot usefully keep
+/// running without it); if an essential task ends in error or panics, the shared
+/// [ShutdownSignal] is tripped so every other task gets a chance to notice and wind down too. Use
+/// [TaskManager::spawn]/[TaskManager::spawn_essential] when `TaskManager` should spawn the thread
+/// itself, or [TaskManager::register]/[TaskManager::register_essential] to adopt a handle to a
+/// thread some other component (e.g. [InboundMessageService](crate::inbound_message_service::InboundMessageService))
+/// already spawned as part of its own `start()`.
+pub struct TaskManager {
+    shutdown: Arc<AtomicBool>,
+    task_count: AtomicUsize,
+    result_tx: mpsc::Sender<TaskResult>,
+    result_rx: Mutex<mpsc::Receiver<TaskResult>>,
+}
+
+impl TaskManager {
+    pub fn new() -> Self {
+        let (result_tx, result_rx) = mpsc::channel();
+        Self {
+            shutdown: Arc::new(AtomicBool::new(false)),
+            task_count: AtomicUsize::new(0),
+            result_tx,
+            result_rx: Mutex::new(result_rx),
+        }
+    }
+
+    /// A clone of the shutdown flag, to be handed to anything spawned so it knows when to stop.
+    pub fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.shutdown.clone())
+    }
+
+    /// Trip the shutdown signal for every task that is watching it.
+    pub fn signal_shutdown(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Adopt a handle to a thread that is already running, reporting its outcome as non-essential
+    /// when it eventually joins.
+    pub fn register<T, E>(&self, name: &'static str, handle: JoinHandle<Result<T, E>>)
+    where
+        T: Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        self.register_with(name, false, handle)
+    }
+
+    /// Adopt a handle to a thread that is already running. If it ends in error or panics, the
+    /// whole node is signalled to shut down via [TaskManager::signal_shutdown].
+    pub fn register_essential<T, E>(&self, name: &'static str, handle: JoinHandle<Result<T, E>>)
+    where
+        T: Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        self.register_with(name, true, handle)
+    }
+
+    /// Spawn `f` on a new thread and register the resulting handle as non-essential, reporting its
+    /// outcome as a [TaskResult] when it eventually joins. Prefer this over spawning the thread
+    /// yourself and calling [TaskManager::register] - it's one call instead of two and can't
+    /// forget to register what it spawns.
+    pub fn spawn<F, T, E>(&self, name: &'static str, f: F)
+    where
+        F: FnOnce() -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        self.register(name, std::thread::spawn(f));
+    }
+
+    /// Spawn `f` on a new thread and register the resulting handle as essential. If it ends in
+    /// error or panics, the whole node is signalled to shut down via [TaskManager::signal_shutdown].
+    pub fn spawn_essential<F, T, E>(&self, name: &'static str, f: F)
+    where
+        F: FnOnce() -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        self.register_essential(name, std::thread::spawn(f));
+    }
+
+    fn register_with<T, E>(&self, name: &'static str, essential: bool, handle: JoinHandle<Result<T, E>>)
+    where
+        T: Send + 'static,
+        E: Debug + Send + 'static,
+    {
+        self.task_count.fetch_add(1, Ordering::SeqCst);
+        let tx = self.result_tx.clone();
+        let shutdown = self.shutdown.clone();
+        // The task has already been spawned elsewhere; this monitor thread's only job is to wait
+        // for it to finish and fold the outcome into the TaskManager's results.
+        std::thread::spawn(move || {
+            let outcome = match handle.join() {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(err)) => Err(format!("{:?}", err)),
+                Err(_) => Err("task panicked".to_owned()),
+            };
+            if outcome.is_err() && essential {
+                shutdown.store(true, Ordering::SeqCst);
+            }
+            let _ = tx.send(TaskResult { name, essential, outcome });
+        });
+    }
+
+    /// Fire the shutdown signal and wait up to `timeout` for every registered task to join,
+    /// returning whatever results arrived in time. Tasks that don't report back within the
+    /// timeout are left running and simply absent from the returned list.
+    pub fn join_all(&self, timeout: Duration) -> Vec<TaskResult> {
+        self.signal_shutdown();
+
+        let rx = self.result_rx.lock().unwrap();
+        let expected = self.task_count.load(Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+
+        let mut results = Vec::with_capacity(expected);
+        while results.len() < expected {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.as_nanos() == 0 {
+                break;
+            }
+            match rx.recv_timeout(remaining) {
+                Ok(result) => results.push(result),
+                Err(_) => break,
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn join_all_collects_success() {
+        let manager = TaskManager::new();
+        let handle: JoinHandle<Result<(), ()>> = thread::spawn(|| Ok(()));
+        manager.register("test-task", handle);
+
+        let results = manager.join_all(Duration::from_secs(1));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].outcome.is_ok());
+    }
+
+    #[test]
+    fn spawn_collects_success() {
+        let manager = TaskManager::new();
+        manager.spawn::<_, (), ()>("test-task", || Ok(()));
+
+        let results = manager.join_all(Duration::from_secs(1));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].outcome.is_ok());
+    }
+
+    #[test]
+    fn essential_task_failure_trips_shutdown_signal() {
+        let manager = TaskManager::new();
+        let signal = manager.shutdown_signal();
+        let handle: JoinHandle<Result<(), &'static str>> = thread::spawn(|| Err("boom"));
+        manager.register_essential("essential-task", handle);
+
+        // join_all trips the signal itself, so read the result straight off the channel instead:
+        // the monitor thread trips the signal before it sends the failed outcome.
+        let results: Vec<TaskResult> = manager
+            .result_rx
+            .lock()
+            .unwrap()
+            .recv_timeout(Duration::from_secs(1))
+            .into_iter()
+            .collect();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].outcome.is_err());
+        assert!(signal.is_triggered());
+    }
+}